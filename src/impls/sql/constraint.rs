@@ -0,0 +1,57 @@
+use rusqlite::ErrorCode;
+
+use cqrs_es2::Error;
+
+/// Message prefix used whenever a write is rejected because another
+/// writer already advanced the same row. Callers that need to tell
+/// this apart from other storage errors (to reload and retry, say)
+/// can match on this prefix, since the shared `cqrs_es2::Error` type
+/// does not expose typed variants of its own.
+pub static OPTIMISTIC_LOCK_ERROR: &str = "optimistic lock conflict";
+
+/// Translates a `rusqlite` write error into an `Error`, recognizing a
+/// unique-constraint violation (two writers racing on the same
+/// `(aggregate_type, aggregate_id, ...)` row) as an optimistic lock
+/// conflict rather than a generic storage failure.
+pub fn map_write_error(
+    context: &str,
+    e: rusqlite::Error,
+) -> Error {
+    if let rusqlite::Error::SqliteFailure(ref sqlite_error, _) = e {
+        if sqlite_error.code == ErrorCode::ConstraintViolation
+            && (sqlite_error.extended_code
+                == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                || sqlite_error.extended_code
+                    == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+        {
+            return Error::new(
+                format!(
+                    "{}: {} with error: {}",
+                    OPTIMISTIC_LOCK_ERROR, context, e
+                )
+                .as_str(),
+            );
+        }
+    }
+
+    Error::new(
+        format!("unable to {} with error: {}", context, e).as_str(),
+    )
+}
+
+/// `true` when `error` was raised by `map_write_error` or
+/// `version_conflict_error` for an optimistic lock conflict
+pub fn is_optimistic_lock_error(error: &Error) -> bool {
+    error
+        .to_string()
+        .starts_with(OPTIMISTIC_LOCK_ERROR)
+}
+
+/// Builds the error for a conditional write (an `UPDATE` gated on the
+/// expected prior version) that matched zero rows, meaning another
+/// writer already moved the row past the version this write expected.
+pub fn version_conflict_error(context: &str) -> Error {
+    Error::new(
+        format!("{}: {}", OPTIMISTIC_LOCK_ERROR, context).as_str(),
+    )
+}
@@ -0,0 +1,427 @@
+use log::{
+    debug,
+    trace,
+};
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+
+use deadpool_sqlite::Pool;
+
+use rusqlite::params;
+
+use cqrs_es2::{
+    Error,
+    IAggregate,
+    ICommand,
+    IEvent,
+    IQuery,
+    QueryContext,
+};
+
+use crate::repository::IAsyncQueryStore;
+
+use super::super::constraint::{
+    map_write_error,
+    version_conflict_error,
+};
+use super::super::mysql_constants::*;
+use super::migrations::run_migrations;
+use super::query_store::UPDATE_QUERY_IF_CURRENT;
+
+/// Async SQLite storage, backed by a `deadpool_sqlite` connection
+/// pool instead of a single owned `rusqlite::Connection`. Every
+/// `save_query`/`load_query` call checks out a pooled connection and
+/// runs the blocking `rusqlite` work on the pool's worker thread via
+/// `interact`, so many aggregates can be loaded and saved
+/// concurrently without blocking the async executor.
+pub struct AsyncQueryStore<
+    C: ICommand,
+    E: IEvent,
+    A: IAggregate<C, E>,
+    Q: IQuery<C, E>,
+> {
+    pool: Pool,
+    _phantom: PhantomData<(C, E, A, Q)>,
+}
+
+impl<
+        C: ICommand,
+        E: IEvent,
+        A: IAggregate<C, E>,
+        Q: IQuery<C, E>,
+    > AsyncQueryStore<C, E, A, Q>
+{
+    /// Constructor. Runs the same migrations as the sync
+    /// `QueryStore` against a connection checked out of `pool`.
+    pub async fn new(pool: Pool) -> Result<Self, Error> {
+        let conn = match pool.get().await {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to check out a pooled connection \
+                         with error: {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        match conn
+            .interact(|conn| run_migrations(conn))
+            .await
+        {
+            Ok(x) => x?,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to run migrations on the pool with \
+                         error: {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        Ok(Self {
+            pool,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+#[async_trait]
+impl<
+        C: ICommand,
+        E: IEvent,
+        A: IAggregate<C, E>,
+        Q: IQuery<C, E>,
+    > IAsyncQueryStore<C, E, Q> for AsyncQueryStore<C, E, A, Q>
+{
+    /// saves the updated query
+    async fn save_query(
+        &self,
+        context: QueryContext<C, E, Q>,
+    ) -> Result<(), Error> {
+        let aggregate_type = A::aggregate_type();
+        let query_type = Q::query_type();
+        let aggregate_id = context.aggregate_id.clone();
+
+        debug!(
+            "storing a new query for aggregate id '{}'",
+            &aggregate_id
+        );
+
+        let payload = match serde_json::to_string(&context.payload) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to serialize the payload of query \
+                         '{}' with aggregate id '{}', error: {}",
+                        &query_type, &aggregate_id, e,
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        let conn = match self.pool.get().await {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to check out a pooled connection \
+                         with error: {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        let version = context.version;
+        let aggregate_id_for_error = aggregate_id.clone();
+
+        let result = conn
+            .interact(move |conn| {
+                if version == 1 {
+                    conn.execute(
+                        INSERT_QUERY,
+                        params![
+                            version,
+                            payload,
+                            aggregate_type,
+                            aggregate_id,
+                            query_type,
+                        ],
+                    )
+                } else {
+                    conn.execute(
+                        UPDATE_QUERY_IF_CURRENT,
+                        params![
+                            version,
+                            payload,
+                            aggregate_type,
+                            aggregate_id,
+                            query_type,
+                            version - 1,
+                        ],
+                    )
+                }
+            })
+            .await;
+
+        let rows_affected = match result {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => {
+                return Err(map_write_error(
+                    format!(
+                        "insert/update query for aggregate id '{}'",
+                        &aggregate_id_for_error
+                    )
+                    .as_str(),
+                    e,
+                ));
+            },
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to run query write on the pool \
+                         with error: {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        if rows_affected == 0 {
+            return Err(version_conflict_error(
+                format!(
+                    "query for aggregate id '{}' was not at the \
+                     expected version",
+                    &aggregate_id_for_error
+                )
+                .as_str(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// loads the most recent query
+    async fn load_query(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<QueryContext<C, E, Q>, Error> {
+        let aggregate_type = A::aggregate_type();
+        let query_type = Q::query_type();
+        let aggregate_id = aggregate_id.to_string();
+
+        trace!(
+            "loading query '{}' for aggregate id '{}'",
+            query_type,
+            &aggregate_id
+        );
+
+        let conn = match self.pool.get().await {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to check out a pooled connection \
+                         with error: {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        let loaded_id = aggregate_id.clone();
+
+        let result = conn
+            .interact(move |conn| {
+                let mut sql = conn.prepare(SELECT_QUERY)?;
+
+                let rows: Result<Vec<(i64, String)>, rusqlite::Error> =
+                    sql.query_map(
+                        params![
+                            aggregate_type,
+                            loaded_id,
+                            query_type
+                        ],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )?
+                    .collect();
+
+                rows
+            })
+            .await;
+
+        let rows = match result {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to load queries table with error: \
+                         {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to run query load on the pool with \
+                         error: {}",
+                        e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        if rows.is_empty() {
+            trace!(
+                "returning default query for aggregate id '{}'",
+                &aggregate_id
+            );
+
+            return Ok(QueryContext::new(
+                aggregate_id,
+                0,
+                Default::default(),
+            ));
+        }
+
+        let (version, payload) = rows[0].clone();
+
+        let payload = match serde_json::from_str(payload.as_str()) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "bad payload found in queries table for \
+                         aggregate id '{}', error: {}",
+                        &aggregate_id, e,
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        Ok(QueryContext::new(aggregate_id, version, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use deadpool_sqlite::{
+        Config,
+        PoolConfig,
+        Runtime,
+    };
+
+    use cqrs_es2::example_impl::{
+        Customer,
+        CustomerCommand,
+        CustomerEvent,
+        CustomerQuery,
+    };
+
+    use super::super::super::constraint::is_optimistic_lock_error;
+    use super::*;
+
+    type TestStore = AsyncQueryStore<
+        CustomerCommand,
+        CustomerEvent,
+        Customer,
+        CustomerQuery,
+    >;
+
+    // A pool capped at one connection keeps every `interact` call
+    // pinned to the same in-memory database; a larger pool could hand
+    // a later checkout a newly opened, independent `:memory:`
+    // connection.
+    async fn new_store() -> TestStore {
+        let mut cfg = Config::new(":memory:");
+        cfg.pool = Some(PoolConfig::new(1));
+
+        let pool = cfg.create_pool(Runtime::Tokio1).unwrap();
+
+        TestStore::new(pool).await.unwrap()
+    }
+
+    // mirrors `query_store::tests::racing_inserts_surface_an_optimistic_lock_conflict`
+    #[tokio::test]
+    async fn racing_inserts_surface_an_optimistic_lock_conflict() {
+        let store = new_store().await;
+
+        let first = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            1,
+            Default::default(),
+        );
+        let second = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            1,
+            Default::default(),
+        );
+
+        store
+            .save_query(first)
+            .await
+            .expect("first writer should win the race");
+
+        let conflict = store
+            .save_query(second)
+            .await
+            .expect_err("second writer should lose the race");
+
+        assert!(is_optimistic_lock_error(&conflict));
+    }
+
+    // mirrors `query_store::tests::racing_updates_surface_an_optimistic_lock_conflict`
+    #[tokio::test]
+    async fn racing_updates_surface_an_optimistic_lock_conflict() {
+        let store = new_store().await;
+
+        store
+            .save_query(QueryContext::new(
+                "test-aggregate-id".to_string(),
+                1,
+                Default::default(),
+            ))
+            .await
+            .expect("the initial insert should succeed");
+
+        let first_update = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            2,
+            Default::default(),
+        );
+        let second_update = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            2,
+            Default::default(),
+        );
+
+        store
+            .save_query(first_update)
+            .await
+            .expect("first writer should win the race");
+
+        let conflict = store
+            .save_query(second_update)
+            .await
+            .expect_err("second writer should lose the race");
+
+        assert!(is_optimistic_lock_error(&conflict));
+    }
+}
@@ -0,0 +1,261 @@
+use log::debug;
+use std::marker::PhantomData;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use std::time::Duration;
+
+use rusqlite::{
+    params,
+    Connection,
+};
+
+use cqrs_es2::{
+    Error,
+    IAggregate,
+    ICommand,
+    IEvent,
+};
+
+use crate::repository::{
+    ILockingStore,
+    LockGuard,
+    UnlockOnDrop,
+};
+
+use super::migrations::run_migrations;
+
+static TRY_LOCK: &str = "
+INSERT INTO locks (aggregate_type, aggregate_id)
+VALUES (?1, ?2)
+ON CONFLICT (aggregate_type, aggregate_id) DO NOTHING;
+";
+
+static UNLOCK: &str = "
+DELETE FROM locks
+WHERE aggregate_type = ?1 AND aggregate_id = ?2;
+";
+
+/// how long to sleep between lock attempts while `lock` is contended,
+/// when the caller does not override it with `with_poll_interval`
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// SQLite lock storage.
+///
+/// Exclusive access to an aggregate id is a row in the `locks` table,
+/// taken with an `INSERT ... ON CONFLICT DO NOTHING` and released
+/// with a `DELETE` when the guard drops. Each of those statements is
+/// a short, auto-committed write, so contention on one aggregate id
+/// never holds SQLite's single database write lock for any longer
+/// than that one statement, and never blocks inserts/deletes for a
+/// different aggregate id. Since the lock is a real row, not
+/// in-process state, it is held against every connection touching
+/// the same database file, including ones opened by other processes
+/// of a horizontally-scaled service.
+pub struct LockStore<
+    C: ICommand,
+    E: IEvent,
+    A: IAggregate<C, E>,
+> {
+    conn: Arc<Mutex<Connection>>,
+    poll_interval: Duration,
+    _phantom: PhantomData<(C, E, A)>,
+}
+
+impl<
+        C: ICommand,
+        E: IEvent,
+        A: IAggregate<C, E>,
+    > LockStore<C, E, A>
+{
+    /// Constructor
+    pub fn new(mut conn: Connection) -> Result<Self, Error> {
+        run_migrations(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// overrides how long `lock` sleeps between attempts while
+    /// contended
+    pub fn with_poll_interval(
+        mut self,
+        poll_interval: Duration,
+    ) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    fn try_lock(
+        &self,
+        aggregate_type: &str,
+        aggregate_id: &str,
+    ) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        match conn.execute(
+            TRY_LOCK,
+            params![aggregate_type, aggregate_id],
+        ) {
+            Ok(rows_affected) => Ok(rows_affected > 0),
+            Err(e) => Err(Error::new(
+                format!(
+                    "unable to try locking aggregate id '{}' with \
+                     error: {}",
+                    aggregate_id, e
+                )
+                .as_str(),
+            )),
+        }
+    }
+}
+
+impl<
+        C: ICommand,
+        E: IEvent,
+        A: IAggregate<C, E>,
+    > Clone for LockStore<C, E, A>
+{
+    /// clones share the same underlying connection and `locks`
+    /// table, so a lock taken through one handle is honored by every
+    /// other handle cloned from it
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            poll_interval: self.poll_interval,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<
+        C: ICommand,
+        E: IEvent,
+        A: IAggregate<C, E>,
+    > ILockingStore for LockStore<C, E, A>
+{
+    /// polls, at `poll_interval`, until a row for `aggregate_id` can
+    /// be inserted into `locks`, then returns a guard that deletes it
+    /// on `Drop`; locks on other aggregate ids are unaffected
+    fn lock(
+        &mut self,
+        aggregate_id: &str,
+    ) -> Result<LockGuard, Error> {
+        let aggregate_type = A::aggregate_type();
+
+        while !self.try_lock(&aggregate_type, aggregate_id)? {
+            thread::sleep(self.poll_interval);
+        }
+
+        debug!("locked aggregate id '{}'", aggregate_id);
+
+        Ok(Box::new(SqliteLockGuard {
+            conn: self.conn.clone(),
+            aggregate_type,
+            aggregate_id: aggregate_id.to_string(),
+        }))
+    }
+}
+
+struct SqliteLockGuard {
+    conn: Arc<Mutex<Connection>>,
+    aggregate_type: String,
+    aggregate_id: String,
+}
+
+impl UnlockOnDrop for SqliteLockGuard {}
+
+impl Drop for SqliteLockGuard {
+    fn drop(&mut self) {
+        let conn = self.conn.lock().unwrap();
+
+        let _ = conn.execute(
+            UNLOCK,
+            params![self.aggregate_type, self.aggregate_id],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration as StdDuration;
+
+    use cqrs_es2::example_impl::{
+        Customer,
+        CustomerCommand,
+        CustomerEvent,
+    };
+
+    use super::*;
+
+    type TestLockStore =
+        LockStore<CustomerCommand, CustomerEvent, Customer>;
+
+    fn new_store() -> TestLockStore {
+        TestLockStore::new(Connection::open_in_memory().unwrap())
+            .unwrap()
+            .with_poll_interval(StdDuration::from_millis(5))
+    }
+
+    #[test]
+    fn lock_on_one_aggregate_does_not_block_another() {
+        let mut store = new_store();
+
+        let _guard_a = store
+            .lock("aggregate-a")
+            .expect("locking aggregate-a should succeed");
+
+        let mut other_handle = store.clone();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let _guard_b = other_handle
+                .lock("aggregate-b")
+                .expect("locking aggregate-b should succeed");
+            tx.send(()).unwrap();
+        });
+
+        rx.recv_timeout(StdDuration::from_secs(1)).expect(
+            "locking a different aggregate id should not block on \
+             the first lock",
+        );
+    }
+
+    #[test]
+    fn a_second_lock_on_the_same_aggregate_waits_for_the_first() {
+        let mut store = new_store();
+
+        let guard_a = store
+            .lock("aggregate-a")
+            .expect("locking aggregate-a should succeed");
+
+        let mut other_handle = store.clone();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let _guard_a_again = other_handle
+                .lock("aggregate-a")
+                .expect("locking aggregate-a should succeed");
+            tx.send(()).unwrap();
+        });
+
+        assert!(
+            rx.recv_timeout(StdDuration::from_millis(200)).is_err(),
+            "the second lock should not be granted while the first \
+             is still held"
+        );
+
+        drop(guard_a);
+
+        rx.recv_timeout(StdDuration::from_secs(1)).expect(
+            "the second lock should be granted once the first is \
+             released",
+        );
+    }
+}
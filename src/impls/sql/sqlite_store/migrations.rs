@@ -0,0 +1,134 @@
+use log::debug;
+
+use rusqlite::Connection;
+
+use cqrs_es2::Error;
+
+/// A single, ordered schema change. `version` must be strictly
+/// greater than every migration that precedes it in `MIGRATIONS`.
+pub struct Migration {
+    pub version: i64,
+    pub up_sql: &'static str,
+}
+
+static CREATE_QUERIES_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS
+queries
+(
+    aggregate_type TEXT                        NOT NULL,
+    aggregate_id   TEXT                        NOT NULL,
+    query_type     TEXT                        NOT NULL,
+    version        bigint CHECK (version >= 0) NOT NULL,
+    payload        TEXT                        NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, query_type)
+);
+";
+
+static CREATE_SNAPSHOTS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS
+snapshots
+(
+    aggregate_type TEXT                              NOT NULL,
+    aggregate_id   TEXT                              NOT NULL,
+    last_sequence  bigint CHECK (last_sequence >= 0) NOT NULL,
+    payload        TEXT                              NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id)
+);
+";
+
+/// ordered list of schema changes applied to a fresh or outdated
+/// database; add new entries to the end, never reorder or remove one
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        up_sql: CREATE_QUERIES_TABLE,
+    },
+    Migration {
+        version: 2,
+        up_sql: CREATE_SNAPSHOTS_TABLE,
+    },
+];
+
+/// Brings the database reachable through `conn` up to date by
+/// applying every migration whose version exceeds the one recorded
+/// in `PRAGMA user_version`, each inside its own transaction, then
+/// records the new version. Intended to run once, at store
+/// construction, so that `save_query`/`load_query`/`save_snapshot`/
+/// `load_snapshot` no longer have to issue DDL on every call.
+pub fn run_migrations(conn: &mut Connection) -> Result<(), Error> {
+    let current_version: i64 = match conn.query_row(
+        "PRAGMA user_version;",
+        [],
+        |row| row.get(0),
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(Error::new(
+                format!(
+                    "unable to read schema version with error: {}",
+                    e
+                )
+                .as_str(),
+            ));
+        },
+    };
+
+    for migration in MIGRATIONS {
+        if migration.version <= current_version {
+            continue;
+        }
+
+        let tx = match conn.transaction() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(Error::new(
+                    format!(
+                        "unable to start migration transaction for \
+                         version {} with error: {}",
+                        migration.version, e
+                    )
+                    .as_str(),
+                ));
+            },
+        };
+
+        if let Err(e) = tx.execute(migration.up_sql, []) {
+            return Err(Error::new(
+                format!(
+                    "unable to apply migration {} with error: {}",
+                    migration.version, e
+                )
+                .as_str(),
+            ));
+        }
+
+        if let Err(e) = tx.pragma_update(
+            None,
+            "user_version",
+            migration.version,
+        ) {
+            return Err(Error::new(
+                format!(
+                    "unable to record schema version {} with \
+                     error: {}",
+                    migration.version, e
+                )
+                .as_str(),
+            ));
+        }
+
+        if let Err(e) = tx.commit() {
+            return Err(Error::new(
+                format!(
+                    "unable to commit migration {} with error: {}",
+                    migration.version, e
+                )
+                .as_str(),
+            ));
+        }
+
+        debug!("applied schema migration {}", migration.version);
+    }
+
+    Ok(())
+}
@@ -24,19 +24,20 @@ use crate::repository::{
     IQueryStore,
 };
 
+use super::super::constraint::{
+    map_write_error,
+    version_conflict_error,
+};
 use super::super::mysql_constants::*;
+use super::migrations::run_migrations;
 
-static CREATE_QUERY_TABLE: &str = "
-CREATE TABLE IF NOT EXISTS
-queries
-(
-    aggregate_type TEXT                        NOT NULL,
-    aggregate_id   TEXT                        NOT NULL,
-    query_type     TEXT                        NOT NULL,
-    version        bigint CHECK (version >= 0) NOT NULL,
-    payload        TEXT                        NOT NULL,
-    PRIMARY KEY (aggregate_type, aggregate_id, query_type)
-);
+pub(super) static UPDATE_QUERY_IF_CURRENT: &str = "
+UPDATE queries
+SET version = ?1, payload = ?2
+WHERE aggregate_type = ?3
+  AND aggregate_id = ?4
+  AND query_type = ?5
+  AND version = ?6;
 ";
 
 /// SQLite storage
@@ -57,35 +58,15 @@ impl<
         Q: IQuery<C, E>,
     > QueryStore<C, E, A, Q>
 {
-    /// Constructor
-    pub fn new(conn: Connection) -> Self {
-        Self {
+    /// Constructor. `save_query`/`load_query` no longer run DDL on
+    /// every call, since `run_migrations` settles the schema here.
+    pub fn new(mut conn: Connection) -> Result<Self, Error> {
+        run_migrations(&mut conn)?;
+
+        Ok(Self {
             conn,
             _phantom: PhantomData,
-        }
-    }
-
-    fn create_query_table(&mut self) -> Result<(), Error> {
-        match self
-            .conn
-            .execute(CREATE_QUERY_TABLE, [])
-        {
-            Ok(_) => {},
-            Err(e) => {
-                return Err(Error::new(
-                    format!(
-                        "unable to create queries table with error: \
-                         {}",
-                        e
-                    )
-                    .as_str(),
-                ));
-            },
-        };
-
-        debug!("Created queries table");
-
-        Ok(())
+        })
     }
 }
 
@@ -101,8 +82,6 @@ impl<
         &mut self,
         context: QueryContext<C, E, Q>,
     ) -> Result<(), Error> {
-        self.create_query_table()?;
-
         let aggregate_type = A::aggregate_type();
         let query_type = Q::query_type();
 
@@ -113,11 +92,6 @@ impl<
             &aggregate_id
         );
 
-        let sql = match context.version {
-            1 => INSERT_QUERY,
-            _ => UPDATE_QUERY,
-        };
-
         let payload = match serde_json::to_string(&context.payload) {
             Ok(x) => x,
             Err(e) => {
@@ -132,29 +106,72 @@ impl<
             },
         };
 
-        match self.conn.execute(
-            sql,
-            params![
-                context.version,
-                payload,
-                aggregate_type,
-                aggregate_id,
-                query_type,
-            ],
-        ) {
-            Ok(x) => x,
-            Err(e) => {
-                return Err(Error::new(
-                    format!(
-                        "unable to insert/update query for \
-                         aggregate id '{}' with error: {}",
-                        &aggregate_id, e
-                    )
-                    .as_str(),
-                ));
+        // a version of 1 means this is the first time we are
+        // persisting this query, so there is no prior row whose
+        // version could have been raced; every later write is
+        // conditioned on the expected prior version, and affecting
+        // zero rows means another writer got there first
+        let rows_affected = match context.version {
+            1 => match self.conn.execute(
+                INSERT_QUERY,
+                params![
+                    context.version,
+                    payload,
+                    aggregate_type,
+                    aggregate_id,
+                    query_type,
+                ],
+            ) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(map_write_error(
+                        format!(
+                            "insert query for aggregate id '{}'",
+                            &aggregate_id
+                        )
+                        .as_str(),
+                        e,
+                    ));
+                },
+            },
+            _ => match self.conn.execute(
+                UPDATE_QUERY_IF_CURRENT,
+                params![
+                    context.version,
+                    payload,
+                    aggregate_type,
+                    aggregate_id,
+                    query_type,
+                    context.version - 1,
+                ],
+            ) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(map_write_error(
+                        format!(
+                            "update query for aggregate id '{}'",
+                            &aggregate_id
+                        )
+                        .as_str(),
+                        e,
+                    ));
+                },
             },
         };
 
+        if rows_affected == 0 {
+            return Err(version_conflict_error(
+                format!(
+                    "query '{}' for aggregate id '{}' was not at \
+                     the expected version {}",
+                    &query_type,
+                    &aggregate_id,
+                    context.version - 1,
+                )
+                .as_str(),
+            ));
+        }
+
         Ok(())
     }
 
@@ -163,8 +180,6 @@ impl<
         &mut self,
         aggregate_id: &str,
     ) -> Result<QueryContext<C, E, Q>, Error> {
-        self.create_query_table()?;
-
         let aggregate_type = A::aggregate_type();
         let query_type = Q::query_type();
 
@@ -265,3 +280,95 @@ impl<
         self.dispatch_events(aggregate_id, events)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cqrs_es2::example_impl::{
+        Customer,
+        CustomerCommand,
+        CustomerEvent,
+        CustomerQuery,
+    };
+
+    use super::super::super::constraint::is_optimistic_lock_error;
+    use super::*;
+
+    type TestStore =
+        QueryStore<CustomerCommand, CustomerEvent, Customer, CustomerQuery>;
+
+    // Two threads racing `save_query` at `version == 1` both attempt
+    // the same `INSERT`; whichever commits first wins the row and
+    // the loser hits the `aggregate_type`/`aggregate_id`/`query_type`
+    // primary key, which is the same constraint violation a real
+    // race would trip. Driving it sequentially against one in-memory
+    // connection exercises that conflict path deterministically,
+    // without the flakiness of coordinating two real threads/
+    // connections in a unit test.
+    #[test]
+    fn racing_inserts_surface_an_optimistic_lock_conflict() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut store = TestStore::new(conn).unwrap();
+
+        let first = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            1,
+            Default::default(),
+        );
+        let second = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            1,
+            Default::default(),
+        );
+
+        store
+            .save_query(first)
+            .expect("first writer should win the race");
+
+        let conflict = store
+            .save_query(second)
+            .expect_err("second writer should lose the race");
+
+        assert!(is_optimistic_lock_error(&conflict));
+    }
+
+    // Once a row exists at version 1, two writers racing
+    // `save_query` at `version == 2` both attempt
+    // `UPDATE_QUERY_IF_CURRENT ... WHERE version = 1`; whichever
+    // commits first moves the row to version 2, leaving the other's
+    // `UPDATE` matching zero rows, the path the version check (rather
+    // than the primary key) is responsible for catching.
+    #[test]
+    fn racing_updates_surface_an_optimistic_lock_conflict() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut store = TestStore::new(conn).unwrap();
+
+        store
+            .save_query(QueryContext::new(
+                "test-aggregate-id".to_string(),
+                1,
+                Default::default(),
+            ))
+            .expect("the initial insert should succeed");
+
+        let first_update = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            2,
+            Default::default(),
+        );
+        let second_update = QueryContext::new(
+            "test-aggregate-id".to_string(),
+            2,
+            Default::default(),
+        );
+
+        store
+            .save_query(first_update)
+            .expect("first writer should win the race");
+
+        let conflict = store
+            .save_query(second_update)
+            .expect_err("second writer should lose the race");
+
+        assert!(is_optimistic_lock_error(&conflict));
+    }
+}
@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+
+use cqrs_es2::{
+    Error,
+    ICommand,
+    IEvent,
+    IQuery,
+    QueryContext,
+};
+
+/// Async counterpart of `IQueryStore`, for backends that hold a
+/// connection pool rather than a single owned connection and can
+/// therefore serve many aggregates concurrently instead of
+/// serializing every load/save on one blocking connection.
+#[async_trait]
+pub trait IAsyncQueryStore<
+    C: ICommand,
+    E: IEvent,
+    Q: IQuery<C, E>,
+>
+{
+    /// saves the updated query
+    async fn save_query(
+        &self,
+        context: QueryContext<C, E, Q>,
+    ) -> Result<(), Error>;
+
+    /// loads the most recent query
+    async fn load_query(
+        &self,
+        aggregate_id: &str,
+    ) -> Result<QueryContext<C, E, Q>, Error>;
+}
@@ -0,0 +1,25 @@
+use cqrs_es2::Error;
+
+/// Marker for a held lock that is released when the guard is
+/// dropped. Backend-specific guards (a persisted lock row, a Redis
+/// key with a TTL, ...) implement this and are returned from
+/// `ILockingStore::lock` behind a `LockGuard` trait object so callers
+/// do not need to know which backend produced it. Whether the lock is
+/// visible only within this process or to every process sharing the
+/// same store depends on the backend; see the implementing type's
+/// docs.
+pub trait UnlockOnDrop: Send {}
+
+/// An RAII guard over an exclusive lock on a single aggregate id.
+/// Dropping it releases the lock.
+pub type LockGuard = Box<dyn UnlockOnDrop>;
+
+/// A pessimistic alternative to optimistic-lock retries: acquire
+/// exclusive access to one aggregate id up front, for the duration of
+/// a load-decide-commit cycle, instead of reloading and retrying
+/// after a version conflict.
+pub trait ILockingStore {
+    /// blocks until exclusive access to `aggregate_id` is acquired,
+    /// returning a guard that releases it on `Drop`
+    fn lock(&mut self, aggregate_id: &str) -> Result<LockGuard, Error>;
+}
@@ -0,0 +1,39 @@
+use cqrs_es2::{
+    AggregateContext,
+    Error,
+    IAggregate,
+    ICommand,
+    IEvent,
+};
+
+/// Persists and restores a serialized copy of an aggregate's full
+/// state, so that a large event stream does not have to be replayed
+/// in full on every load.
+///
+/// Mirrors `IQueryStore` in shape: a store keyed on aggregate id that
+/// saves and loads a single row per aggregate, except the payload
+/// here is the aggregate itself rather than a read-model query.
+pub trait ISnapshotStore<
+    C: ICommand,
+    E: IEvent,
+    A: IAggregate<C, E>,
+>
+{
+    /// persists the given aggregate as the snapshot for
+    /// `aggregate_id`, recording `last_sequence` as the highest event
+    /// sequence folded into it
+    fn save_snapshot(
+        &mut self,
+        aggregate_id: &str,
+        aggregate: &A,
+        last_sequence: i64,
+    ) -> Result<(), Error>;
+
+    /// loads the stored snapshot for `aggregate_id`, if any, and
+    /// catches it up with events committed since it was taken,
+    /// returning the combined aggregate context
+    fn load_snapshot(
+        &mut self,
+        aggregate_id: &str,
+    ) -> Result<Option<AggregateContext<C, E, A>>, Error>;
+}